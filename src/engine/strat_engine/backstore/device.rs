@@ -7,10 +7,16 @@
 use std::collections::HashMap;
 use std::fmt::{self, Display};
 use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::prelude::AsRawFd;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str;
 
+use crc::crc32;
+use libc;
 use libudev;
+use uuid::Uuid;
 
 use devicemapper::{devnode_to_devno, Bytes, Device};
 
@@ -18,7 +24,6 @@ use stratis::{ErrorEnum, StratisError, StratisResult};
 
 use super::super::super::types::{DevUuid, PoolUuid};
 
-use super::metadata::device_identifiers;
 use super::udev::{get_udev_property, udev_block_device_apply, unclaimed};
 
 ioctl!(read blkgetsize64 with 0x12, 114; u64);
@@ -58,7 +63,16 @@ pub fn resolve_devices<'a>(paths: &'a [&Path]) -> StratisResult<HashMap<Device,
 /// changes.
 #[derive(Debug, PartialEq, Eq)]
 pub enum TheirsReason {
-    /// Udev identifies device as belonging to another.
+    /// Device is a LUKS/crypto member.
+    Luks,
+    /// Device is an LVM physical volume.
+    Lvm,
+    /// Device is a member of an MD RAID array.
+    MdRaid,
+    /// Device holds a partition table of the given type.
+    PartitionTable(String),
+    /// Udev identifies device as belonging to another, but to no category
+    /// that Stratis recognizes specifically.
     Udev {
         id_part_table_type: Option<String>,
         id_fs_type: Option<String>,
@@ -68,6 +82,12 @@ pub enum TheirsReason {
 impl Display for TheirsReason {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            TheirsReason::Luks => write!(f, "device is a LUKS member"),
+            TheirsReason::Lvm => write!(f, "device is an LVM physical volume"),
+            TheirsReason::MdRaid => write!(f, "device is an MD RAID member"),
+            TheirsReason::PartitionTable(table) => {
+                write!(f, "device holds a {} partition table", table)
+            }
             TheirsReason::Udev {
                 id_part_table_type,
                 id_fs_type,
@@ -87,6 +107,24 @@ impl Display for TheirsReason {
     }
 }
 
+/// Classify a device that udev reports as claimed by someone other than
+/// Stratis, distinguishing the common foreign owners that Stratis must never
+/// clobber from the generic case.
+fn theirs_reason(id_fs_type: Option<String>, id_part_table_type: Option<String>) -> TheirsReason {
+    match id_fs_type.as_ref().map(|val| val.as_str()) {
+        Some("crypto_LUKS") => TheirsReason::Luks,
+        Some("LVM2_member") => TheirsReason::Lvm,
+        Some("linux_raid_member") => TheirsReason::MdRaid,
+        _ => match id_part_table_type {
+            Some(table) => TheirsReason::PartitionTable(table),
+            None => TheirsReason::Udev {
+                id_part_table_type,
+                id_fs_type,
+            },
+        },
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum DevOwnership {
     Contradiction,
@@ -95,43 +133,203 @@ pub enum DevOwnership {
     Theirs(TheirsReason),
 }
 
+/// Open a block device read-only for reading its Stratis signature, adding
+/// the given flags to O_NOATIME.
+/// O_NOATIME avoids writing a fresh access time to a device that is merely
+/// being probed; it is only permitted to the file's owner and fails with
+/// EPERM otherwise, so that specific error is caught and the open retried
+/// without the flag.
+fn open_block_device_with(devnode: &Path, extra_flags: libc::c_int) -> StratisResult<File> {
+    match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOATIME | extra_flags)
+        .open(devnode)
+    {
+        Ok(file) => Ok(file),
+        // O_NOATIME is only allowed for the file's owner; retry without it.
+        Err(ref err) if err.raw_os_error() == Some(libc::EPERM) => Ok(OpenOptions::new()
+            .read(true)
+            .custom_flags(extra_flags)
+            .open(devnode)?),
+        Err(err) => Err(StratisError::from(err)),
+    }
+}
+
+/// Open a block device for reading its Stratis signature with O_DIRECT, so
+/// that header reads bypass the page cache. This matters while the same
+/// device is being rewritten by thin_repair/create_fs: a cached page could
+/// otherwise return stale header bytes. The reads must be block-aligned in
+/// offset, length, and buffer; callers use sector offsets and AlignedSector
+/// buffers to satisfy that.
+fn open_block_device(devnode: &Path) -> StratisResult<File> {
+    open_block_device_with(devnode, libc::O_DIRECT)
+}
+
+// The Stratis static header is written near the start of every device that
+// Stratis owns. It consists of two identical signature blocks -- a primary
+// and a backup -- each occupying a single sector. Reading it does not depend
+// on libblkid, and its per-block CRC32 lets a partially written or
+// coincidentally matching block be told apart from a genuine signature.
+//
+// Signature block layout, relative to the start of the block (SECTOR_SIZE
+// bytes):
+//      0..4    CRC32 (Castagnoli) over bytes 4..SECTOR_SIZE, little-endian
+//      4..20   magic byte string (16 bytes)
+//     20..28   device size in sectors (u64, little-endian)
+//        28    signature block format version (1 byte)
+//     32..64   pool UUID, 32 ASCII hex digits
+//     64..96   device UUID, 32 ASCII hex digits
+const SECTOR_SIZE: usize = 512;
+const STRATIS_SIGBLOCK_SECTORS: [u64; 2] = [1, 9];
+const STRATIS_MAGIC: &[u8] = b"!Stra0tis\x86\xff\x02^\x41rh";
+const STRATIS_SIGBLOCK_VERSION: u8 = 1;
+
+/// A sector-sized buffer aligned to the logical block size, so that it can
+/// be used for O_DIRECT reads.
+#[repr(align(512))]
+struct AlignedSector([u8; SECTOR_SIZE]);
+
+/// What a single signature block says about a device.
+enum Sigblock {
+    /// A well-formed block naming the device's pool and device UUIDs.
+    Identified(PoolUuid, DevUuid),
+    /// The magic matched but the block is unusable: its CRC32 did not verify
+    /// or its version is one this build does not understand.
+    Corrupt,
+    /// No Stratis magic is present in this block.
+    Absent,
+}
+
+/// The result of reading a device's Stratis static header.
+enum HeaderId {
+    /// The header names the device's pool and device UUIDs.
+    Ours(PoolUuid, DevUuid),
+    /// A Stratis header is present but unusable.
+    Corrupt,
+    /// No Stratis header is present.
+    Absent,
+}
+
+/// Parse one of the header's 32-character ASCII-hex UUID fields.
+fn parse_uuid(bytes: &[u8]) -> StratisResult<Uuid> {
+    let err = |msg: String| StratisError::Engine(ErrorEnum::Invalid, msg);
+    let s = str::from_utf8(bytes).map_err(|e| err(e.to_string()))?;
+    Uuid::parse_str(s).map_err(|e| err(e.to_string()))
+}
+
+/// Interpret a single signature block.
+fn read_sigblock(buf: &[u8; SECTOR_SIZE]) -> StratisResult<Sigblock> {
+    if &buf[4..4 + STRATIS_MAGIC.len()] != STRATIS_MAGIC {
+        return Ok(Sigblock::Absent);
+    }
+
+    let stored_crc = u32::from(buf[0])
+        | (u32::from(buf[1]) << 8)
+        | (u32::from(buf[2]) << 16)
+        | (u32::from(buf[3]) << 24);
+    if stored_crc != crc32::checksum_castagnoli(&buf[4..]) {
+        return Ok(Sigblock::Corrupt);
+    }
+
+    if buf[28] != STRATIS_SIGBLOCK_VERSION {
+        return Ok(Sigblock::Corrupt);
+    }
+
+    Ok(Sigblock::Identified(
+        parse_uuid(&buf[32..64])?,
+        parse_uuid(&buf[64..96])?,
+    ))
+}
+
+/// Read both signature blocks from a device.
+/// The device is opened with O_DIRECT so the header is read from disk rather
+/// than from a possibly stale cached page. Some backing stores refuse
+/// O_DIRECT reads with EINVAL even though the open succeeded; in that case
+/// the probe falls back to a buffered open.
+fn read_sigblocks(devnode: &Path) -> StratisResult<[AlignedSector; 2]> {
+    fn read_into(f: &mut File) -> io::Result<[AlignedSector; 2]> {
+        let mut blocks = [
+            AlignedSector([0u8; SECTOR_SIZE]),
+            AlignedSector([0u8; SECTOR_SIZE]),
+        ];
+        for (block, &sector) in blocks.iter_mut().zip(STRATIS_SIGBLOCK_SECTORS.iter()) {
+            f.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+            f.read_exact(&mut block.0)?;
+        }
+        Ok(blocks)
+    }
+
+    match read_into(&mut open_block_device(devnode)?) {
+        Ok(blocks) => Ok(blocks),
+        Err(ref err) if err.raw_os_error() == Some(libc::EINVAL) => {
+            Ok(read_into(&mut open_block_device_with(devnode, 0)?)?)
+        }
+        Err(err) => Err(StratisError::from(err)),
+    }
+}
+
+/// Determine a device's ownership from its Stratis static header alone.
+/// The primary signature block is consulted first, falling back to the
+/// backup. A block whose magic is present but whose CRC32 does not verify
+/// (or whose version is unsupported) yields Corrupt rather than Absent, so a
+/// partial write is never mistaken for unowned space.
+fn stratis_header_id(devnode: &Path) -> StratisResult<HeaderId> {
+    let mut corrupt = false;
+    for block in &read_sigblocks(devnode)? {
+        match read_sigblock(&block.0)? {
+            Sigblock::Identified(pool_uuid, dev_uuid) => {
+                return Ok(HeaderId::Ours(pool_uuid, dev_uuid));
+            }
+            Sigblock::Corrupt => corrupt = true,
+            Sigblock::Absent => {}
+        }
+    }
+    Ok(if corrupt {
+        HeaderId::Corrupt
+    } else {
+        HeaderId::Absent
+    })
+}
+
 /// Identify a device node using a combination of udev information and
 /// Stratis signature information.
 /// Return an error if the device is not in the udev database.
 /// Return an error if the necessary udev information can not be read.
 pub fn identify(devnode: &Path) -> StratisResult<DevOwnership> {
-    /// A helper function. None if the device is unclaimed, the value of
-    /// ID_FS_TYPE, which may yet be None, if it is.
-    #[allow(option_option)]
-    fn udev_info(device: &libudev::Device) -> StratisResult<Option<Option<String>>> {
+    /// A helper function. None if the device is unclaimed, otherwise the
+    /// values of ID_FS_TYPE and ID_PART_TABLE_TYPE, either of which may yet
+    /// be None.
+    fn udev_info(
+        device: &libudev::Device,
+    ) -> StratisResult<Option<(Option<String>, Option<String>)>> {
         if unclaimed(device) {
             Ok(None)
         } else {
-            Ok(Some(get_udev_property(device, "ID_FS_TYPE")?))
+            Ok(Some((
+                get_udev_property(device, "ID_FS_TYPE")?,
+                get_udev_property(device, "ID_PART_TABLE_TYPE")?,
+            )))
         }
     }
 
     match udev_block_device_apply(devnode, udev_info)? {
-        Some(Ok(Some(Some(value)))) => {
-            if value == "stratis" {
-                if let Some((pool_uuid, device_uuid)) =
-                    device_identifiers(&mut OpenOptions::new().read(true).open(&devnode)?)?
-                {
-                    Ok(DevOwnership::Ours(pool_uuid, device_uuid))
-                } else {
-                    Ok(DevOwnership::Contradiction)
-                }
+        Some(Ok(Some((id_fs_type, id_part_table_type)))) => {
+            if id_fs_type.as_ref().map_or(false, |value| value == "stratis") {
+                // udev asserts this is a Stratis device, so a missing header
+                // is itself a contradiction.
+                Ok(match stratis_header_id(devnode)? {
+                    HeaderId::Ours(pool_uuid, device_uuid) => {
+                        DevOwnership::Ours(pool_uuid, device_uuid)
+                    }
+                    HeaderId::Corrupt | HeaderId::Absent => DevOwnership::Contradiction,
+                })
             } else {
-                Ok(DevOwnership::Theirs(TheirsReason::Udev {
-                    id_part_table_type: None,
-                    id_fs_type: None,
-                }))
+                Ok(DevOwnership::Theirs(theirs_reason(
+                    id_fs_type,
+                    id_part_table_type,
+                )))
             }
         }
-        Some(Ok(Some(None))) => Ok(DevOwnership::Theirs(TheirsReason::Udev {
-            id_part_table_type: None,
-            id_fs_type: None,
-        })),
         Some(Ok(None)) => {
             // Not a Stratis device OR running an older version of libblkid
             // that does not interpret Stratis devices. Fall back on reading
@@ -143,12 +341,12 @@ pub fn identify(devnode: &Path) -> StratisResult<DevOwnership> {
             // stateful global variable. So, instead, fall back on the safe
             // approach of just always reading the Stratis header, regardless
             // of what has happened in the past.
-            Ok(if let Some((pool_uuid, device_uuid)) =
-                device_identifiers(&mut OpenOptions::new().read(true).open(&devnode)?)?
-            {
-                DevOwnership::Ours(pool_uuid, device_uuid)
-            } else {
-                DevOwnership::Unowned
+            Ok(match stratis_header_id(devnode)? {
+                HeaderId::Ours(pool_uuid, device_uuid) => {
+                    DevOwnership::Ours(pool_uuid, device_uuid)
+                }
+                HeaderId::Corrupt => DevOwnership::Contradiction,
+                HeaderId::Absent => DevOwnership::Unowned,
             })
         }
         Some(Err(err)) => Err(err),
@@ -162,6 +360,46 @@ pub fn identify(devnode: &Path) -> StratisResult<DevOwnership> {
     }
 }
 
+/// Retrieve all the Stratis devices currently present on the system.
+/// Construct a libudev enumeration restricted to block devices, run every
+/// entry through identify(), and group the devices that belong to Stratis
+/// by their pool UUID.
+/// A device that can not be identified, or that identify() classifies as
+/// belonging to someone else, is simply omitted from the result; an error
+/// encountered while probing a single device is logged and the scan
+/// continues, so that one unreadable device can not hide every pool on the
+/// system.
+pub fn find_all() -> StratisResult<HashMap<PoolUuid, HashMap<DevUuid, PathBuf>>> {
+    let context = libudev::Context::new()?;
+    let mut enumerator = libudev::Enumerator::new(&context)?;
+    enumerator.match_subsystem("block")?;
+
+    let mut pools: HashMap<PoolUuid, HashMap<DevUuid, PathBuf>> = HashMap::new();
+    for device in enumerator.scan_devices()? {
+        let devnode = match device.devnode() {
+            Some(devnode) => devnode.to_owned(),
+            None => continue,
+        };
+
+        match identify(&devnode) {
+            Ok(DevOwnership::Ours(pool_uuid, dev_uuid)) => {
+                pools
+                    .entry(pool_uuid)
+                    .or_insert_with(HashMap::new)
+                    .insert(dev_uuid, devnode);
+            }
+            Ok(_) => {}
+            Err(err) => warn!(
+                "Could not identify block device {}, omitting it from the scan: {}",
+                devnode.display(),
+                err
+            ),
+        }
+    }
+
+    Ok(pools)
+}
+
 #[cfg(test)]
 mod test {
 
@@ -233,4 +471,61 @@ mod test {
     pub fn real_test_device_empty() {
         real::test_with_spec(real::DeviceLimits::AtLeast(1, None, None), test_empty);
     }
+
+    /// Verify that theirs_reason maps each recognized ID_FS_TYPE to its
+    /// specific foreign-owner variant, that a bare partition table becomes a
+    /// PartitionTable, and that anything else falls through to the generic
+    /// Udev variant.
+    #[test]
+    fn test_theirs_reason() {
+        let fs = |val: &str| Some(val.to_string());
+
+        assert_eq!(theirs_reason(fs("crypto_LUKS"), None), TheirsReason::Luks);
+        assert_eq!(theirs_reason(fs("LVM2_member"), None), TheirsReason::Lvm);
+        assert_eq!(
+            theirs_reason(fs("linux_raid_member"), None),
+            TheirsReason::MdRaid
+        );
+        assert_eq!(
+            theirs_reason(None, fs("gpt")),
+            TheirsReason::PartitionTable("gpt".to_string())
+        );
+        assert_eq!(
+            theirs_reason(fs("ext4"), None),
+            TheirsReason::Udev {
+                id_part_table_type: None,
+                id_fs_type: fs("ext4"),
+            }
+        );
+    }
+
+    /// Verify that find_all enumerates the system without reporting the empty
+    /// test devices: they are Unowned, so they must not appear in any pool's
+    /// device map.
+    fn test_find_all_skips_unowned(paths: &[&Path]) {
+        cmd::udev_settle().unwrap();
+
+        let pools = find_all().unwrap();
+        for path in paths {
+            assert!(!pools
+                .values()
+                .any(|devs| devs.values().any(|devnode| devnode.as_path() == **path)));
+        }
+    }
+
+    #[test]
+    pub fn loop_test_find_all_skips_unowned() {
+        loopbacked::test_with_spec(
+            loopbacked::DeviceLimits::Range(1, 3, None),
+            test_find_all_skips_unowned,
+        );
+    }
+
+    #[test]
+    pub fn real_test_find_all_skips_unowned() {
+        real::test_with_spec(
+            real::DeviceLimits::AtLeast(1, None, None),
+            test_find_all_skips_unowned,
+        );
+    }
 }
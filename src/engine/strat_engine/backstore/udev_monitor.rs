@@ -0,0 +1,158 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Watch udev for block devices appearing and disappearing at runtime.
+// Unlike identify(), which is a pull API invoked on demand, this module
+// wraps a long-lived libudev::Monitor filtered to the block subsystem and
+// turns raw udev events into typed Stratis events. An "add" (or "change")
+// event is run through the existing identify() path so that a device which
+// has just grown a Stratis signature is reported as Ours, allowing a
+// partially-present pool to be completed once its final member appears. A
+// "remove" event simply reports the path that went away.
+
+use std::io;
+use std::os::unix::prelude::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use libc;
+use libudev;
+
+use stratis::{StratisError, StratisResult};
+
+use super::super::super::types::{DevUuid, PoolUuid};
+
+use super::device::{identify, DevOwnership};
+
+/// An event of interest to the engine, distilled from a raw udev event.
+/// Devices that are not Stratis's are not reported as additions; the engine
+/// only needs to learn about devices it may be able to claim or must forget.
+#[derive(Debug, PartialEq, Eq)]
+pub enum UdevEngineEvent {
+    /// A block device carrying a Stratis signature appeared.
+    Added(PoolUuid, DevUuid, PathBuf),
+    /// A block device was removed.
+    Removed(PathBuf),
+}
+
+/// A pollable wrapper around a libudev block-device monitor.
+/// The monitor may be polled directly via poll(), iterated over, or handed
+/// to a background thread with spawn() that forwards events over a channel.
+pub struct UdevMonitor {
+    socket: libudev::MonitorSocket,
+}
+
+impl UdevMonitor {
+    /// Construct a monitor listening for events on the block subsystem.
+    pub fn new(context: &libudev::Context) -> StratisResult<UdevMonitor> {
+        let mut monitor = libudev::Monitor::new(context)?;
+        monitor.match_subsystem("block")?;
+        Ok(UdevMonitor {
+            socket: monitor.listen()?,
+        })
+    }
+
+    /// Translate a single raw udev event into an engine event, if it is one
+    /// the engine cares about. Returns Ok(None) for events that carry no
+    /// device node, for non-add/remove/change events, and for additions of
+    /// devices that do not belong to Stratis.
+    fn interpret(event: &libudev::Event) -> StratisResult<Option<UdevEngineEvent>> {
+        let devnode = match event.device().devnode() {
+            Some(devnode) => devnode.to_owned(),
+            None => return Ok(None),
+        };
+
+        match event.event_type() {
+            libudev::EventType::Add | libudev::EventType::Change => {
+                match identify(&devnode)? {
+                    DevOwnership::Ours(pool_uuid, dev_uuid) => {
+                        Ok(Some(UdevEngineEvent::Added(pool_uuid, dev_uuid, devnode)))
+                    }
+                    _ => Ok(None),
+                }
+            }
+            libudev::EventType::Remove => Ok(Some(UdevEngineEvent::Removed(devnode))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Receive the next event on the monitor socket, if one is waiting, and
+    /// interpret it. Returns Ok(None) when no event is pending or when the
+    /// pending event is not of interest to the engine. This call does not
+    /// block; combine it with a poll on the monitor's file descriptor.
+    pub fn poll(&mut self) -> StratisResult<Option<UdevEngineEvent>> {
+        match self.socket.receive_event() {
+            Some(event) => UdevMonitor::interpret(&event),
+            None => Ok(None),
+        }
+    }
+
+    /// Block until the monitor's file descriptor has an event ready to read.
+    /// A poll interrupted by a signal is retried rather than surfaced.
+    fn wait_readable(&self) -> StratisResult<()> {
+        let mut pfd = libc::pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            if unsafe { libc::poll(&mut pfd, 1, -1) } < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::EINTR) {
+                    continue;
+                }
+                return Err(StratisError::from(err));
+            }
+            return Ok(());
+        }
+    }
+
+    /// Run the monitor in a background thread, forwarding every engine event
+    /// over the returned channel. Errors encountered while interpreting an
+    /// individual event are logged and skipped so that the monitor keeps
+    /// running. Once the receiving end of the channel has been dropped, the
+    /// thread ends at the next engine event it tries to forward.
+    pub fn spawn(mut self) -> Receiver<UdevEngineEvent> {
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            for event in &mut self {
+                match event {
+                    Ok(Some(event)) => {
+                        if sender.send(event).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(err) => warn!("Ignoring udev event that could not be processed: {}", err),
+                }
+            }
+        });
+        receiver
+    }
+}
+
+impl AsRawFd for UdevMonitor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl Iterator for UdevMonitor {
+    type Item = StratisResult<Option<UdevEngineEvent>>;
+
+    fn next(&mut self) -> Option<StratisResult<Option<UdevEngineEvent>>> {
+        // receive_event() is non-blocking and returns None whenever the
+        // socket is momentarily empty; block on the fd first so that a
+        // transient empty read is never mistaken for the end of the stream.
+        loop {
+            if let Err(err) = self.wait_readable() {
+                return Some(Err(err));
+            }
+            if let Some(event) = self.socket.receive_event() {
+                return Some(UdevMonitor::interpret(&event));
+            }
+        }
+    }
+}